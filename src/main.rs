@@ -1,21 +1,45 @@
-extern crate rand;
 extern crate libusb;
+extern crate ctrlc;
+extern crate crossbeam;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 
 use std::str;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::io::Write;
 use std::fs::File;
+use std::thread;
 use std::time::Duration;
-use std::u8;
-use rand::Rng;
+use std::time::Instant;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::collections::HashMap;
 
 type Temprature = f32;
 
 
 trait TempratureSensor {
     fn sensor_name(&self) -> &str;
-    fn sensor_read(&mut self) -> Temprature;
+
+    // the latest temprature, or None when the read failed so the caller
+    // can skip the update rather than record a fabricated value
+    fn sensor_read(&mut self) -> Option<Temprature>;
+
+    // relative humidity in percent, for probes that measure it; plain
+    // temprature sensors leave the default and report nothing
+    fn sensor_humidity(&self) -> Option<f32> {
+        return None;
+    }
+
+    // claim/release the underlying interface; sensors backed by a file
+    // have nothing to claim and keep the default no-ops
+    fn claim(&mut self) {}
+    fn release(&mut self) {}
 }
 
 
@@ -50,7 +74,7 @@ impl TempratureSensor for SysfsSensor {
         return self.name.as_str();
     }
 
-    fn sensor_read(&mut self) -> Temprature {
+    fn sensor_read(&mut self) -> Option<Temprature> {
         let mut buf: [u8; 32] = [0; 32];
 
         // read from start of file
@@ -58,7 +82,110 @@ impl TempratureSensor for SysfsSensor {
         let result = self.file.read(&mut buf).unwrap();
         let temp_str = str::from_utf8(&buf[0..result - 1]).unwrap();
         let temp_f32 = (temp_str.parse::<i32>().unwrap() as f32) / 1000.0;
-        return temp_f32;
+        return Some(temp_f32);
+    }
+}
+
+
+/*
+ * USB humidity/temprature probe using an SHT1x-style sensor
+ *
+ * the device reports a raw 14-bit temprature word and a 12-bit humidity
+ * word which are converted with the datasheet coefficients: temprature is
+ * linear, humidity is a quadratic linearization that is then temprature
+ * compensated and clamped into [0, 100].
+ */
+struct Sht1xSensor<'a> {
+    name: String,
+    handle: libusb::DeviceHandle<'a>,
+    interface: u8,
+    humidity: Option<f32>,
+}
+
+
+impl<'a> Sht1xSensor<'a> {
+    // open a probe selected by vendor/product id in main
+    fn open(sensor_name: &str, device: &'a libusb::Device) -> Sht1xSensor<'a> {
+        return Sht1xSensor {
+            name: sensor_name.to_string(),
+            handle: device.open().unwrap(),
+            interface: 0x00,
+            humidity: None,
+        }
+    }
+
+    // read the raw temprature (SOt, 14 bit) and humidity (SOrh, 12 bit)
+    // words from the probe, or None on a failed/short transfer
+    fn read_raw(&mut self) -> Option<(u16, u16)> {
+        let mut buf: [u8; 8] = [0; 8];
+        let result = match self.handle.read_interrupt(0x81, &mut buf, Duration::from_secs(1)) {
+            Ok(result) => result,
+            Err(_) => return None,
+        };
+        if result < 4 {
+            return None;
+        }
+        let so_t = (((buf[0] as u16) << 8) | (buf[1] as u16)) & 0x3fff;
+        let so_rh = (((buf[2] as u16) << 8) | (buf[3] as u16)) & 0x0fff;
+        return Some((so_t, so_rh));
+    }
+
+    // apply the datasheet conversion to the raw words
+    fn convert(so_t: u16, so_rh: u16) -> (Temprature, f32) {
+        let d1 = -40.0;
+        let d2 = 0.01;
+        let temp = d1 + d2 * so_t as f32;
+
+        let c1 = -4.0;
+        let c2 = 0.0405;
+        let c3 = -2.8e-6;
+        let rh_lin = c1 + c2 * so_rh as f32 + c3 * (so_rh as f32) * (so_rh as f32);
+
+        let t1 = 0.01;
+        let t2 = 0.00008;
+        let mut rh_true = (temp - 25.0) * (t1 + t2 * so_rh as f32) + rh_lin;
+        if rh_true < 0.0 {
+            rh_true = 0.0;
+        }
+        if rh_true > 100.0 {
+            rh_true = 100.0;
+        }
+        return (temp, rh_true);
+    }
+}
+
+
+impl<'a> TempratureSensor for Sht1xSensor<'a> {
+    fn sensor_name(&self) -> &str {
+        return self.name.as_str();
+    }
+
+    fn sensor_read(&mut self) -> Option<Temprature> {
+        let (so_t, so_rh) = match self.read_raw() {
+            Some(raw) => raw,
+            None => {
+                // a failed read clears the humidity and signals "no
+                // reading" rather than a fabricated -40 C
+                self.humidity = None;
+                return None;
+            }
+        };
+        let (temp, humidity) = Sht1xSensor::convert(so_t, so_rh);
+        self.humidity = Some(humidity);
+        return Some(temp);
+    }
+
+    fn sensor_humidity(&self) -> Option<f32> {
+        return self.humidity;
+    }
+
+    fn claim(&mut self) {
+        self.handle.detach_kernel_driver(self.interface);
+        self.handle.claim_interface(self.interface);
+    }
+
+    fn release(&mut self) {
+        self.handle.release_interface(self.interface);
     }
 }
 
@@ -74,23 +201,9 @@ struct Status {
 
 
 impl Status {
-    fn decode_status(buf: &[u8], verbose: bool) -> Status {
-        // bytes 7 to 16 are usually the same
-        let expected: [u8; 10] = [0x00, 0x00, 0x00, 0xff, 0x02, 0x00, 0x01, 0x08, 0x1e, 0x00];
-        if verbose {
-            if buf.len() != 17 {
-                println!("Unexpected status length: {}", buf.len());
-            }
-            if buf[0] != 0x04 {
-                println!("Unexpected first byte: {}", buf[0]);
-            }
-            for i in 7..buf.len() {
-                let expected_byte = expected[i - 7];
-                if buf[i] != expected_byte {
-                    println!("Unexpected byte[{}]: {:02x}, expected {:02x}", i, buf[i], expected_byte);
-                }
-            }
-        }
+    fn decode_status(buf: &[u8]) -> Status {
+        // no anomaly printing here: UsbTrace captures the raw transfers so
+        // they can be diffed against known-good dumps
         if buf.len() > 6 {
             return Status {
                 temp: (buf[1] as f32) + (buf[2] as f32 / 9.0),
@@ -109,6 +222,71 @@ impl Status {
 }
 
 
+/*
+ * maps temprature to a fan or pump duty cycle
+ *
+ * control points are interpolated linearly and clamped at the ends. a
+ * hysteresis band keeps the duty fixed until the temprature moves more
+ * than the configured number of degrees from the last setpoint, so small
+ * fractional changes no longer trigger a write on every poll.
+ */
+struct FanCurve {
+    points: Vec<(Temprature, u8)>,
+    hysteresis: Temprature,
+    last_temp: Temprature,
+    last_duty: u8,
+}
+
+
+impl FanCurve {
+    fn new(points: Vec<(Temprature, u8)>, hysteresis: Temprature) -> FanCurve {
+        return FanCurve {
+            points: points,
+            hysteresis: hysteresis,
+            last_temp: 0.0,
+            last_duty: 0,
+        }
+    }
+
+    // linear interpolation between the two surrounding control points,
+    // clamped to the first and last duty outside the curve's range
+    fn duty_at(&self, temp: Temprature) -> u8 {
+        if self.points.is_empty() {
+            return 0;
+        }
+        let first = self.points[0];
+        if temp <= first.0 {
+            return first.1;
+        }
+        for window in self.points.windows(2) {
+            let low = window[0];
+            let high = window[1];
+            if temp <= high.0 {
+                let span = high.0 - low.0;
+                if span <= 0.0 {
+                    return high.1;
+                }
+                let ratio = (temp - low.0) / span;
+                let duty = low.1 as f32 + ratio * (high.1 as f32 - low.1 as f32);
+                return duty as u8;
+            }
+        }
+        return self.points[self.points.len() - 1].1;
+    }
+
+    // returns a new duty only when the temprature has moved outside the
+    // hysteresis band, otherwise the previous setpoint is held
+    fn update(&mut self, temp: Temprature) -> u8 {
+        if (temp - self.last_temp).abs() <= self.hysteresis {
+            return self.last_duty;
+        }
+        self.last_temp = temp;
+        self.last_duty = self.duty_at(temp);
+        return self.last_duty;
+    }
+}
+
+
 /*
  * a single color
  */
@@ -121,31 +299,195 @@ struct RGB {
 
 
 impl RGB {
-    fn rand() -> RGB {
-        RGB {r: rand::random(), g: rand::random(), b: rand::random()}
+    fn from_tuple(c: (u8, u8, u8)) -> RGB {
+        return RGB {r: c.0, g: c.1, b: c.2};
     }
 }
 
 
-fn color_msg(mode: u8, seq: u8, text: RGB, colors: &[RGB; 8]) -> [u8; 32] {
+/*
+ * direction of a recorded interrupt transfer
+ */
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Direction {
+    In,
+    Out,
+}
+
+
+/*
+ * usbmon-style capture of interrupt transfers
+ *
+ * every transfer is logged with a monotonic timestamp, the endpoint
+ * address, its direction, the payload length and a hex dump, in a
+ * line-oriented format that can be diffed against known-good dumps. an
+ * optional endpoint/direction filter narrows the capture the same way a
+ * raw usbmon trace can be filtered.
+ */
+struct UsbTrace {
+    start: Instant,
+    file: Option<File>,
+    filter_endpoint: Option<u8>,
+    filter_direction: Option<Direction>,
+}
+
+
+impl UsbTrace {
+    fn disabled() -> UsbTrace {
+        return UsbTrace {
+            start: Instant::now(),
+            file: None,
+            filter_endpoint: None,
+            filter_direction: None,
+        }
+    }
+
+    fn to_file(file_path: &str) -> UsbTrace {
+        return UsbTrace {
+            start: Instant::now(),
+            file: Some(File::create(file_path).unwrap()),
+            filter_endpoint: None,
+            filter_direction: None,
+        }
+    }
+
+    fn filter_endpoint(&mut self, endpoint: u8) {
+        self.filter_endpoint = Some(endpoint);
+    }
+
+    fn filter_direction(&mut self, direction: Direction) {
+        self.filter_direction = Some(direction);
+    }
+
+    fn accepts(&self, endpoint: u8, direction: Direction) -> bool {
+        if let Some(want) = self.filter_endpoint {
+            if want != endpoint {
+                return false;
+            }
+        }
+        if let Some(want) = self.filter_direction {
+            if want != direction {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    fn record(&mut self, endpoint: u8, direction: Direction, payload: &[u8]) {
+        if self.file.is_none() || !self.accepts(endpoint, direction) {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        let stamp = elapsed.as_secs() as f64 + (elapsed.subsec_nanos() as f64 / 1_000_000_000.0);
+        let arrow = match direction {
+            Direction::In => "<",
+            Direction::Out => ">",
+        };
+        let mut hex = String::new();
+        for byte in payload {
+            hex.push_str(&format!("{:02x} ", byte));
+        }
+        let line = format!("{:013.6} ep={:02x} {} len={} {}\n", stamp, endpoint, arrow, payload.len(), hex.trim_end());
+        if let Some(ref mut file) = self.file {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+    }
+}
+
+
+/*
+ * builds a single lighting frame from an explicit per-LED palette
+ *
+ * each of the eight LEDs can carry its own colour, which the animated
+ * effects below need.
+ */
+fn effect_frame(mode: u8, seq: u8, colors: &[RGB; 8]) -> [u8; 32] {
     let mut result: [u8; 32] = [0; 32];
     result[0] = 0x02;
     result[1] = 0x4c;
     result[2] = 0x00;
     result[3] = mode;
     result[4] = 0x02 | ((seq & 0x07) << 5);
-    result[5] = text.g;
-    result[6] = text.r;
-    result[7] = text.b;
+    result[5] = colors[0].g;
+    result[6] = colors[0].r;
+    result[7] = colors[0].b;
     for i in 0..8 {
-        result[i*3 + 8] = text.r;
-        result[i*3 + 9] = text.g;
-        result[i*3 + 10] = text.b;
+        result[i*3 + 8] = colors[i].r;
+        result[i*3 + 9] = colors[i].g;
+        result[i*3 + 10] = colors[i].b;
     }
     return result;
 }
 
 
+// linear blend between two colours, t in [0, 1]
+fn blend(a: RGB, b: RGB, t: f32) -> RGB {
+    let mix = |x: u8, y: u8| (x as f32 + (y as f32 - x as f32) * t) as u8;
+    return RGB {r: mix(a.r, b.r), g: mix(a.g, b.g), b: mix(a.b, b.b)};
+}
+
+
+/*
+ * generates the 8-step colour frames for the lighting animations
+ *
+ * each variant emits the device mode byte and the sequence-indexed frames
+ * it needs. Temperature is host driven and maps the current highest
+ * temprature onto a cold-blue to hot-red gradient; the rest set up a
+ * device-side animation.
+ */
+enum LightingEffect {
+    Breathing(RGB),
+    Fading(Vec<RGB>),
+    Marquee(RGB),
+    Temperature,
+}
+
+
+impl LightingEffect {
+    fn frames(&self, highest_temp: Temprature) -> Vec<[u8; 32]> {
+        let off = RGB {r: 0, g: 0, b: 0};
+        match *self {
+            // one colour pulsed by the device
+            LightingEffect::Breathing(color) => {
+                return vec![effect_frame(0x07, 0, &[color; 8])];
+            }
+            // the device fades through each palette entry in turn
+            LightingEffect::Fading(ref palette) => {
+                let mut frames = Vec::new();
+                for (seq, color) in palette.iter().enumerate() {
+                    frames.push(effect_frame(0x01, seq as u8, &[*color; 8]));
+                }
+                return frames;
+            }
+            // a single lit LED spun around the ring
+            LightingEffect::Marquee(color) => {
+                let mut frames = Vec::new();
+                for seq in 0..8 {
+                    let mut colors = [off; 8];
+                    colors[seq as usize] = color;
+                    frames.push(effect_frame(0x04, seq, &colors));
+                }
+                return frames;
+            }
+            // map 30..70 C onto blue -> red and send a solid colour
+            LightingEffect::Temperature => {
+                let cold = RGB {r: 0, g: 0, b: 255};
+                let hot = RGB {r: 255, g: 0, b: 0};
+                let mut t = (highest_temp - 30.0) / 40.0;
+                if t < 0.0 {
+                    t = 0.0;
+                }
+                if t > 1.0 {
+                    t = 1.0;
+                }
+                let color = blend(cold, hot, t);
+                return vec![effect_frame(0x00, 0, &[color; 8])];
+            }
+        }
+    }
+}
+
+
 /*
  * sets and reads fan and pump speeds
  */
@@ -153,6 +495,10 @@ struct UsbController<'a> {
     name: String,
     handle: libusb::DeviceHandle<'a>,
     interface: u8,
+    fan_curve: FanCurve,
+    pump_curve: FanCurve,
+    trace: UsbTrace,
+    effect: LightingEffect,
 }
 
 
@@ -162,6 +508,10 @@ impl<'a> UsbController<'a> {
             name: sensor_name.to_string(),
             handle: device.open().unwrap(),
             interface: 0x00,
+            fan_curve: FanCurve::new(vec![(30.0, 25), (40.0, 40), (50.0, 70), (60.0, 100)], 2.0),
+            pump_curve: FanCurve::new(vec![(30.0, 60), (45.0, 80), (60.0, 100)], 2.0),
+            trace: UsbTrace::disabled(),
+            effect: LightingEffect::Temperature,
         }
     }
 
@@ -178,10 +528,25 @@ impl<'a> UsbController<'a> {
         return self.name.as_str();
     }
 
-    fn get_status(&mut self) -> Status {
+    // read the current status, or None on a transfer error/timeout so the
+    // task keeps running rather than panicking out of crossbeam::scope
+    fn get_status(&mut self) -> Option<Status> {
         let mut buf: [u8; 64] = [0; 64];
-        let result = self.handle.read_interrupt(0x81, &mut buf, Duration::from_secs(1)).unwrap();
-        return Status::decode_status(&buf[0..result], true);
+        let result = match self.handle.read_interrupt(0x81, &mut buf, Duration::from_secs(1)) {
+            Ok(result) => result,
+            Err(_) => return None,
+        };
+        self.trace.record(0x81, Direction::In, &buf[0..result]);
+        return Some(Status::decode_status(&buf[0..result]));
+    }
+
+    // a failed write is dropped without being traced; a transient USB
+    // timeout must not tear the monitor down
+    fn write(&mut self, buf: &[u8]) {
+        match self.handle.write_interrupt(0x01, buf, Duration::from_secs(1)) {
+            Ok(result) => self.trace.record(0x01, Direction::Out, &buf[0..result]),
+            Err(_) => {}
+        }
     }
 
     fn set_fan(&mut self, fan_speed: u8) {
@@ -189,7 +554,7 @@ impl<'a> UsbController<'a> {
         if fan_speed > 100 {
             buf[4] = 100;
         }
-        let result = self.handle.write_interrupt(0x01, &buf, Duration::from_secs(1)).unwrap();
+        self.write(&buf);
     }
 
     fn set_pump(&mut self, pump_speed: u8) {
@@ -197,22 +562,14 @@ impl<'a> UsbController<'a> {
         if pump_speed > 100 {
             buf[4] = 100;
         }
-        let result = self.handle.write_interrupt(0x01, &buf, Duration::from_secs(1)).unwrap();
-    }
-
-    fn set_color(&mut self, text: RGB, colors: &[RGB; 8]) {
-        let mode = 0x06;
-        let buf = color_msg(mode, 0, text, &colors);
-        let result = self.handle.write_interrupt(0x01, &buf, Duration::from_secs(1)).unwrap();
+        self.write(&buf);
     }
 
-    fn set_color_random(&mut self) {
-        let mode = 0x04;
-        for seq in 0..8 {
-            let text = RGB::rand();
-            let colors = [RGB::rand(); 8];
-            let buf = color_msg(mode, seq, text, &colors);
-            let result = self.handle.write_interrupt(0x01, &buf, Duration::from_secs(1)).unwrap();
+    // render the active lighting effect for the current temprature and
+    // push every frame it produces to the device
+    fn set_effect(&mut self, highest_temp: Temprature) {
+        for buf in self.effect.frames(highest_temp) {
+            self.write(&buf);
         }
     }
 }
@@ -252,9 +609,33 @@ fn print_device(device: &libusb::Device) {
 }
 
 
-struct SensorReading {
-    name: String,
-    value: f32,
+/*
+ * latest temprature reading per sensor, shared between the device tasks
+ * so each one can base its fan logic on the highest reading seen
+ */
+struct Readings {
+    temps: HashMap<String, f32>,
+}
+
+
+impl Readings {
+    fn new() -> Readings {
+        return Readings {temps: HashMap::new()};
+    }
+
+    fn set(&mut self, name: &str, value: f32) {
+        self.temps.insert(name.to_string(), value);
+    }
+
+    fn highest(&self) -> f32 {
+        let mut highest = 0.0;
+        for value in self.temps.values() {
+            if *value > highest {
+                highest = *value;
+            }
+        }
+        return highest;
+    }
 }
 
 
@@ -264,6 +645,7 @@ struct SensorReading {
 struct Monitor<'a> {
     sensor_file: Vec<SysfsSensor>,
     sensor_usb: Vec<UsbController<'a>>,
+    sensor_probe: Vec<Box<TempratureSensor + 'a>>,
 }
 
 
@@ -272,6 +654,7 @@ impl<'a> Monitor<'a> {
         return Monitor {
             sensor_file: Vec::new(),
             sensor_usb: Vec::new(),
+            sensor_probe: Vec::new(),
         }
     }
 
@@ -279,112 +662,365 @@ impl<'a> Monitor<'a> {
         self.sensor_file.push(SysfsSensor::open(sensor_name, filepath));
     }
 
-    fn add_usb_monitor(&mut self, sensor_name: &str, device: &'a libusb::Device) {
-        self.sensor_usb.push(UsbController::open(sensor_name, &device));
-    }
-
-    fn read_tempratures(&mut self) -> Vec<SensorReading> {
-        let mut result = Vec::new();
-        for file_device in self.sensor_file.iter_mut() {
-            result.push(SensorReading{name: file_device.sensor_name().to_string(), value: file_device.sensor_read()});
+    // build a controller from a config entry, overriding the default
+    // curves with the ones declared for this device
+    fn add_usb_monitor_config(&mut self, config: &DeviceConfig, device: &'a libusb::Device) {
+        let mut controller = UsbController::open(&config.name, device);
+        controller.fan_curve = FanCurve::new(config.curve.fan.clone(), config.curve.hysteresis);
+        controller.pump_curve = FanCurve::new(config.curve.pump.clone(), config.curve.hysteresis);
+        if let Some(ref effect) = config.effect {
+            controller.effect = effect.build();
         }
-        for usb_device in self.sensor_usb.iter_mut() {
-            let status = usb_device.get_status();
-            result.push(SensorReading{name: usb_device.sensor_name().to_string(), value: status.temp});
+
+        // derive a per-device trace path so multiple units don't share one
+        // file, and apply any configured endpoint/direction filter
+        let path = config.trace.as_ref().and_then(|t| t.path.clone())
+            .unwrap_or(format!("kraken-{}.trace", config.name));
+        let mut trace = UsbTrace::to_file(&path);
+        if let Some(ref t) = config.trace {
+            if let Some(endpoint) = t.endpoint {
+                trace.filter_endpoint(endpoint);
+            }
+            match t.direction.as_ref().map(|d| d.as_str()) {
+                Some("in") => trace.filter_direction(Direction::In),
+                Some("out") => trace.filter_direction(Direction::Out),
+                _ => {}
+            }
         }
-        return result;
+        controller.trace = trace;
+
+        self.sensor_usb.push(controller);
+    }
+
+    // register any TempratureSensor, such as an SHT1x humidity probe,
+    // to be polled alongside the Kraken and hwmon files
+    fn add_probe_monitor(&mut self, sensor: Box<TempratureSensor + 'a>) {
+        self.sensor_probe.push(sensor);
     }
 
     fn run(&mut self) {
-        for usb_device in self.sensor_usb.iter_mut() {
-            usb_device.claim();
-            usb_device.set_color_random();
-        }
+        // shutdown flag flipped by SIGINT so every task can leave its loop
+        // and reach its release()/cleanup path
+        let running = Arc::new(AtomicBool::new(true));
+        let handler = running.clone();
+        ctrlc::set_handler(move || {
+            handler.store(false, Ordering::SeqCst);
+        }).unwrap();
+
+        // latest reading per sensor, shared so each device task can pick
+        // the highest temprature without polling the others itself
+        let readings = Arc::new(Mutex::new(Readings::new()));
+
+        // one task per sensor and per device: each issues its own blocking
+        // transfers on its own interval, so a slow USB read no longer stalls
+        // the other devices or the sysfs files. libusb's transfers are
+        // blocking, so these are scoped OS threads rather than futures, but
+        // the concurrency and independent-interval goals are the same; each
+        // task absorbs its own transfer errors so a timeout can't tear the
+        // scope down before the release() below.
+        crossbeam::scope(|scope| {
+            for sensor in self.sensor_file.iter_mut() {
+                let running = running.clone();
+                let readings = readings.clone();
+                scope.spawn(move || {
+                    let mut previous = 0.0;
+                    while running.load(Ordering::SeqCst) {
+                        if let Some(value) = sensor.sensor_read() {
+                            readings.lock().unwrap().set(sensor.sensor_name(), value);
+                            if value != previous {
+                                previous = value;
+                                println!("{} Temp {:.2} C", sensor.sensor_name(), value);
+                            }
+                        }
+                        thread::sleep(Duration::from_millis(500));
+                    }
+                });
+            }
 
+            for probe in self.sensor_probe.iter_mut() {
+                let running = running.clone();
+                let readings = readings.clone();
+                scope.spawn(move || {
+                    probe.claim();
+                    let mut previous = 0.0;
+                    while running.load(Ordering::SeqCst) {
+                        if let Some(value) = probe.sensor_read() {
+                            readings.lock().unwrap().set(probe.sensor_name(), value);
+                            if value != previous {
+                                previous = value;
+                                match probe.sensor_humidity() {
+                                    Some(rh) => println!("{} Temp {:.2} C, Humidity {:.1} %", probe.sensor_name(), value, rh),
+                                    None => println!("{} Temp {:.2} C", probe.sensor_name(), value),
+                                }
+                            }
+                        }
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                    probe.release();
+                });
+            }
 
-        let mut previous_temp = 0.0;
-        let mut previous_speed = 0;
-        loop {
-            let temps = self.read_tempratures();
-            let mut highest_temp = 0.0;
-            for temp in temps.iter() {
-                if temp.value > highest_temp {
-                    highest_temp = temp.value;
-                }
+            for usb_device in self.sensor_usb.iter_mut() {
+                let running = running.clone();
+                let readings = readings.clone();
+                scope.spawn(move || {
+                    usb_device.claim();
+                    let mut previous = 0.0;
+                    let mut last_duty: Option<(u8, u8)> = None;
+                    while running.load(Ordering::SeqCst) {
+                        if let Some(status) = usb_device.get_status() {
+                            readings.lock().unwrap().set(usb_device.sensor_name(), status.temp);
+                            let highest = readings.lock().unwrap().highest();
+
+                            // consult the curves, applying hysteresis so the
+                            // duty is only rewritten once the temprature moves
+                            if highest != previous {
+                                previous = highest;
+                                let fan = usb_device.fan_curve.update(highest);
+                                let pump = usb_device.pump_curve.update(highest);
+                                if last_duty != Some((fan, pump)) {
+                                    last_duty = Some((fan, pump));
+                                    println!("Setting fan: {}, pump {}", fan, pump);
+                                    usb_device.set_fan(fan);
+                                    usb_device.set_pump(pump);
+                                }
+                                usb_device.set_effect(highest);
+                            }
+                        }
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                    usb_device.release();
+                });
             }
+        });
+    }
+}
 
-            // printout when values change
-            if highest_temp != previous_temp {
-                previous_temp = highest_temp;
-                for temp in temps {
-                    println!("{} Temp {:.2} C", temp.name, temp.value);
-                }
 
-                // modify fan speed
-                let target_speed = (100.0 * highest_temp / 70.0) as u8;
+/*
+ * a fan or pump curve as declared in the config file
+ */
+#[derive(Deserialize)]
+struct CurveConfig {
+    hysteresis: Temprature,
+    fan: Vec<(Temprature, u8)>,
+    pump: Vec<(Temprature, u8)>,
+}
 
-                // smooth over large changes
-                let adjusted_speed: u32 = ((previous_speed as u32 * 7) + target_speed as u32) / 8;
-                let new_speed = adjusted_speed as u8;
-                previous_speed = new_speed;
 
-                println!("Setting fan: {}, pump {}", new_speed, new_speed);
-                for usb_device in self.sensor_usb.iter_mut() {
-                    //usb_device.set_color(new_speed, 0, 0);
-                    usb_device.set_fan(new_speed);
-                    usb_device.set_pump(new_speed);
-                }
-            }
-        }
+/*
+ * selects a lighting effect and its colours for a device
+ */
+#[derive(Deserialize)]
+struct EffectConfig {
+    mode: String,
+    #[serde(default)]
+    color: Option<(u8, u8, u8)>,
+    #[serde(default)]
+    palette: Option<Vec<(u8, u8, u8)>>,
+}
+
 
-        for usb_device in self.sensor_usb.iter_mut() {
-            usb_device.release();
+impl EffectConfig {
+    fn build(&self) -> LightingEffect {
+        match self.mode.as_str() {
+            "breathing" => LightingEffect::Breathing(RGB::from_tuple(self.color.unwrap_or((0, 0, 255)))),
+            "fading" => {
+                let palette = self.palette.clone().unwrap_or_default();
+                return LightingEffect::Fading(palette.into_iter().map(RGB::from_tuple).collect());
+            }
+            "marquee" => LightingEffect::Marquee(RGB::from_tuple(self.color.unwrap_or((255, 0, 0)))),
+            _ => LightingEffect::Temperature,
         }
     }
 }
 
 
-fn monitor_device(board_temp: &mut SysfsSensor, cpu_temp: &mut SysfsSensor, usb_device: &mut UsbController) {
-    let mut current_temp = 0.0;
-    loop {
-        let status = usb_device.get_status();
-        let board_reading = board_temp.sensor_read();
-        let cpu_reading = cpu_temp.sensor_read();
-        let monitor = (board_reading + cpu_reading + status.temp) as f32 / 3.0;
-        if monitor != current_temp {
-            current_temp = monitor;
-            println!("Board Temp {:.2} C, CPU Temp {:.2} C, Water Temp: {:.2} C, Fan: {} RPM, Pump: {} RPM", board_reading, cpu_reading, status.temp, status.fan, status.pump);
-        }
-    }
+/*
+ * optional capture settings for a device's interrupt transfers
+ *
+ * an absent path defaults to a per-device file so multiple units never
+ * clobber each other; endpoint/direction narrow the capture.
+ */
+#[derive(Deserialize)]
+struct TraceConfig {
+    #[serde(default)]
+    path: Option<String>,
+    #[serde(default)]
+    endpoint: Option<u8>,
+    #[serde(default)]
+    direction: Option<String>,
+}
+
 
+/*
+ * a USB device to claim, with its friendly name and curves
+ */
+#[derive(Deserialize)]
+struct DeviceConfig {
+    name: String,
+    vendor_id: u16,
+    product_id: u16,
+    curve: CurveConfig,
+    #[serde(default)]
+    effect: Option<EffectConfig>,
+    #[serde(default)]
+    trace: Option<TraceConfig>,
 }
 
 
-fn select_device(device: libusb::Device) {
+/*
+ * a hwmon file to monitor under a friendly name
+ */
+#[derive(Deserialize)]
+struct SensorConfig {
+    name: String,
+    path: String,
+}
 
-    // print all device information
-    print_device(&device);
 
-    // add devices to monitor
-    let mut monitor = Monitor::new();
-    //monitor.add_file_monitor("Board", "/sys/class/hwmon/hwmon4/temp2_input");
-    monitor.add_file_monitor("CPU", "/sys/class/hwmon/hwmon0/temp1_input");
-    monitor.add_usb_monitor("Water", &device);
-    monitor.run();
+/*
+ * a USB humidity/temprature probe to open by vendor/product id
+ */
+#[derive(Deserialize)]
+struct ProbeConfig {
+    name: String,
+    vendor_id: u16,
+    product_id: u16,
+}
+
+
+/*
+ * top level declarative configuration
+ *
+ * declares which USB devices to claim, which sysfs files to read, and the
+ * per-device fan/pump curves, so the Monitor is built entirely from config
+ * rather than source edits.
+ */
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    sensor: Vec<SensorConfig>,
+    #[serde(default)]
+    probe: Vec<ProbeConfig>,
+    #[serde(default)]
+    device: Vec<DeviceConfig>,
+}
+
+
+impl Config {
+    fn load(file_path: &str) -> Config {
+        let mut file = File::open(file_path).unwrap();
+        let mut text = String::new();
+        file.read_to_string(&mut text).unwrap();
+        return toml::from_str(&text).unwrap();
+    }
 }
 
 
 fn main() {
-    // usb id
-    let vendor_id = 0x1e71;
-    let product_id = 0x170e;
-    let mut context = libusb::Context::new().unwrap();
+    let config = Config::load("kraken.toml");
+    let context = libusb::Context::new().unwrap();
+
+    // keep the device list alive for as long as the monitor borrows from it
+    let devices = context.devices().unwrap();
+    let device_list: Vec<libusb::Device> = devices.iter().collect();
 
-    // device selection
-    for mut device in context.devices().unwrap().iter() {
+    let mut monitor = Monitor::new();
+    for sensor in config.sensor.iter() {
+        monitor.add_file_monitor(&sensor.name, &sensor.path);
+    }
+
+    // claim every configured USB device present on the bus
+    for device in device_list.iter() {
         let device_desc = device.device_descriptor().unwrap();
-        if device_desc.vendor_id() == vendor_id && device_desc.product_id() == product_id {
-            select_device(device);
+        for dev_cfg in config.device.iter() {
+            if device_desc.vendor_id() == dev_cfg.vendor_id && device_desc.product_id() == dev_cfg.product_id {
+                print_device(device);
+                monitor.add_usb_monitor_config(dev_cfg, device);
+            }
         }
+        for probe_cfg in config.probe.iter() {
+            if device_desc.vendor_id() == probe_cfg.vendor_id && device_desc.product_id() == probe_cfg.product_id {
+                monitor.add_probe_monitor(Box::new(Sht1xSensor::open(&probe_cfg.name, device)));
+            }
+        }
+    }
+
+    monitor.run();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve() -> FanCurve {
+        return FanCurve::new(vec![(30.0, 25), (40.0, 40), (50.0, 70), (60.0, 100)], 2.0);
+    }
+
+    #[test]
+    fn duty_at_control_points() {
+        let c = curve();
+        assert_eq!(c.duty_at(30.0), 25);
+        assert_eq!(c.duty_at(40.0), 40);
+        assert_eq!(c.duty_at(60.0), 100);
+    }
+
+    #[test]
+    fn duty_between_points_is_interpolated() {
+        let c = curve();
+        // halfway from (30,25) to (40,40): 25 + 0.5 * 15 = 32.5 -> 32
+        assert_eq!(c.duty_at(35.0), 32);
+        // halfway from (40,40) to (50,70): 40 + 0.5 * 30 = 55
+        assert_eq!(c.duty_at(45.0), 55);
+    }
+
+    #[test]
+    fn duty_outside_range_is_clamped() {
+        let c = curve();
+        assert_eq!(c.duty_at(10.0), 25);
+        assert_eq!(c.duty_at(90.0), 100);
+    }
+
+    #[test]
+    fn hysteresis_holds_then_releases() {
+        let mut c = curve();
+        assert_eq!(c.update(40.0), 40);
+        // within 2 degrees of the last setpoint: hold
+        assert_eq!(c.update(41.0), 40);
+        // moved far enough: re-evaluate, 40 + 0.3 * 30 = 49
+        assert_eq!(c.update(43.0), 49);
+    }
+
+    #[test]
+    fn sht1x_convert_matches_datasheet() {
+        let (temp, rh) = Sht1xSensor::convert(6000, 1000);
+        assert!((temp - 20.0).abs() < 0.001);
+        assert!((rh - 33.25).abs() < 0.001);
+    }
+
+    #[test]
+    fn sht1x_humidity_is_clamped() {
+        let (_, rh) = Sht1xSensor::convert(0, 0);
+        assert!(rh >= 0.0 && rh <= 100.0);
+    }
+
+    #[test]
+    fn blend_is_midpoint() {
+        let cold = RGB {r: 0, g: 0, b: 255};
+        let hot = RGB {r: 255, g: 0, b: 0};
+        let mid = blend(cold, hot, 0.5);
+        assert_eq!(mid.r, 127);
+        assert_eq!(mid.g, 0);
+        assert_eq!(mid.b, 127);
+    }
+
+    #[test]
+    fn trace_respects_endpoint_filter() {
+        let mut trace = UsbTrace::disabled();
+        trace.filter_endpoint(0x81);
+        assert!(trace.accepts(0x81, Direction::In));
+        assert!(!trace.accepts(0x01, Direction::Out));
     }
 }